@@ -1,303 +1,821 @@
 // SPDX-License-Identifier: Unlicense
 
-use std::path::PathBuf;
+mod backup;
+mod migration;
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 use crate::luhn::AccountNumber;
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
 use rand::prelude::*;
-use rusqlite::{Connection, Result};
+use rusqlite::{Connection, Transaction};
 
 #[derive(Debug)]
 pub struct Account {
     pub id: u64,
     pub account_number: String,
-    pub balance: u64,
     pub pin: String,
 }
 
-#[cfg(not(test))]
-fn database_path() -> PathBuf {
-    PathBuf::from("bank.s3db")
+/// Denomination an account's initial balance is seeded under.
+const DEFAULT_DENOM: &str = "default";
+
+const LEDGER_DEPOSIT: &str = "deposit";
+const LEDGER_WITHDRAWAL: &str = "withdrawal";
+const LEDGER_TRANSFER_DEBIT: &str = "transfer_debit";
+const LEDGER_TRANSFER_CREDIT: &str = "transfer_credit";
+const LEDGER_ACCOUNT_OPENED: &str = "account_opened";
+const LEDGER_ACCOUNT_DELETED: &str = "account_deleted";
+
+/// One append-only row of account history, as returned by `Bank::statement`.
+#[derive(Debug)]
+pub struct LedgerEntry {
+    pub id: u64,
+    pub account_number: String,
+    pub kind: String,
+    pub denom: String,
+    pub amount: i64,
+    pub counterparty: Option<String>,
+    pub balance: u64,
+    pub timestamp: i64,
+    pub transfer_id: Option<i64>,
 }
 
-#[cfg(test)]
-fn database_path() -> PathBuf {
-    PathBuf::from("mock_bank.s3db")
+/// Errors account operations can report, beyond "some sqlite error happened".
+#[derive(Debug)]
+pub enum BankError {
+    Sqlite(rusqlite::Error),
+    Hash(argon2::password_hash::Error),
+    InsufficientFunds,
+    SameAccount,
+    WrongPin,
+    InvalidAmount,
+    Io(std::io::Error),
+    /// Backup file failed to decrypt - wrong passphrase, or corrupted.
+    BadBackup,
+    /// `restore` refused to overwrite a non-empty database without `force`.
+    DatabaseNotEmpty,
 }
 
-pub fn initialise_bankdb() -> Result<Connection> {
-    let db = Connection::open(database_path())?;
-    let command = "CREATE TABLE IF NOT EXISTS account(
-id INTEGER PRIMARY KEY,
-account_number TEXT,
-pin TEXT DEFAULT '000000',
-balance INTEGER DEFAULT 0
-)";
-    db.execute(command, rusqlite::params![])?;
-    Ok(db)
+impl From<rusqlite::Error> for BankError {
+    fn from(err: rusqlite::Error) -> Self {
+        BankError::Sqlite(err)
+    }
 }
 
-pub fn create_account(data: &AccountNumber, balance: u64) -> Result<()> {
-    let db = initialise_bankdb()?;
-    let account_number = data.to_string();
-    let mut stmt = db.prepare("SELECT id, account_number, balance, pin FROM account")?;
-    let accounts = stmt.query_map([], |row| {
-        Ok(Account {
-            id: row.get(0)?,
-            account_number: row.get(1)?,
-            balance: row.get(2)?,
-            pin: row.get(3)?,
-        })
-    })?;
+impl From<argon2::password_hash::Error> for BankError {
+    fn from(err: argon2::password_hash::Error) -> Self {
+        BankError::Hash(err)
+    }
+}
 
-    let get_latest_max_id = {
-        let mut x = 0;
-        for account in accounts.flatten() {
-            if account.id > x {
-                x = account.id
+impl From<std::io::Error> for BankError {
+    fn from(err: std::io::Error) -> Self {
+        BankError::Io(err)
+    }
+}
+
+impl std::fmt::Display for BankError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BankError::Sqlite(err) => write!(f, "database error: {err}"),
+            BankError::Hash(err) => write!(f, "pin hashing error: {err}"),
+            BankError::InsufficientFunds => write!(f, "insufficient funds"),
+            BankError::SameAccount => write!(f, "origin and target account are the same"),
+            BankError::WrongPin => write!(f, "wrong pin"),
+            BankError::InvalidAmount => write!(f, "invalid amount"),
+            BankError::Io(err) => write!(f, "i/o error: {err}"),
+            BankError::BadBackup => write!(f, "backup file is corrupt or the passphrase is wrong"),
+            BankError::DatabaseNotEmpty => {
+                write!(f, "refusing to restore over a non-empty database without force")
             }
         }
-        x
-    };
+    }
+}
+
+impl std::error::Error for BankError {}
 
-    let newest_max_id = get_latest_max_id + 1;
-    let mut rng = thread_rng();
-    let mut pin: Vec<String> = Vec::new();
+type BankResult<T> = std::result::Result<T, BankError>;
 
-    // Six digit pin
-    for _ in 1..=6 {
-        let y = rng.gen_range(0..=9).to_string();
-        pin.push(y);
+/// Hashes a plaintext PIN, producing a PHC-format string for the `pin` column.
+fn hash_pin(pin: &str) -> BankResult<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(pin.as_bytes(), &salt)?;
+    Ok(hash.to_string())
+}
+
+/// Runs `f` inside a real sqlite transaction, committing only if it succeeds.
+fn with_transaction<T>(
+    db: &mut Connection,
+    f: impl FnOnce(&Transaction) -> BankResult<T>,
+) -> BankResult<T> {
+    let tx = db.transaction()?;
+    let result = f(&tx)?;
+    tx.commit()?;
+    Ok(result)
+}
+
+/// Reads the balance an account holds in `denom`, treating "no row yet"
+/// as a balance of zero rather than an error.
+fn balance_in_denom(conn: &Connection, account_number: &str, denom: &str) -> BankResult<u64> {
+    match conn.query_row(
+        "SELECT amount FROM balances WHERE account_number=?1 AND denom=?2",
+        rusqlite::params![account_number, denom],
+        |row| row.get(0),
+    ) {
+        Ok(amount) => Ok(amount),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(0),
+        Err(err) => Err(err.into()),
     }
+}
 
-    let pin: String = String::from_iter(pin);
-
-    let new_account = Account {
-        id: newest_max_id,
-        account_number,
-        balance,
-        pin,
-    };
-
-    db.execute(
-        "INSERT INTO account (id, account_number, pin, balance) VALUES (?1, ?2, ?3, ?4)",
-        rusqlite::params![
-            new_account.id,
-            new_account.account_number,
-            new_account.pin,
-            new_account.balance,
-        ],
+fn now_unix() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before the unix epoch")
+        .as_secs() as i64
+}
+
+/// Appends one row to the ledger, returning its id for callers that need
+/// to link entries (a transfer's debit/credit pair) via `transfer_id`.
+fn record_ledger_entry(
+    tx: &Transaction,
+    account_number: &str,
+    kind: &str,
+    denom: &str,
+    amount: i64,
+    counterparty: Option<&str>,
+    balance: u64,
+    transfer_id: Option<i64>,
+) -> BankResult<i64> {
+    tx.execute(
+        "INSERT INTO ledger (account_number, kind, denom, amount, counterparty, balance, timestamp, transfer_id)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        rusqlite::params![account_number, kind, denom, amount, counterparty, balance, now_unix(), transfer_id],
     )?;
+    Ok(tx.last_insert_rowid())
+}
+
+#[cfg(not(test))]
+fn database_path() -> PathBuf {
+    PathBuf::from("bank.s3db")
+}
+
+#[cfg(test)]
+fn database_path() -> PathBuf {
+    PathBuf::from("mock_bank.s3db")
+}
 
-    Ok(())
+/// A handle onto the bank database, holding one long-lived `Connection`.
+pub struct Bank {
+    conn: Connection,
 }
 
-pub fn deposit(amount: &str, pin: &str, account_number: &str) -> Result<()> {
-    let db = initialise_bankdb()?;
-    let query_string = format!(
-        "SELECT pin FROM account where account_number='{}';",
-        account_number
-    );
+impl Bank {
+    pub fn open() -> BankResult<Self> {
+        Self::open_at(&database_path())
+    }
 
-    let pin_from_db: String = db.query_row(&query_string, [], |row| row.get(0))?;
+    /// Opens (and migrates) the database at `path`, bypassing the shared
+    /// test database - for tests that need one of their own.
+    fn open_at(path: &Path) -> BankResult<Self> {
+        let mut conn = Connection::open(path)?;
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+        migration::apply(&mut conn)?;
+        Ok(Self { conn })
+    }
 
-    let correct_pin = { pin_from_db == pin };
+    /// Creates a new account with a freshly generated six-digit PIN, storing
+    /// only its hash. `Account.pin` is the one and only time the plaintext
+    /// PIN is available - it cannot be recovered afterwards.
+    pub fn create_account(&mut self, data: &AccountNumber, balance: u64) -> BankResult<Account> {
+        let account_number = data.to_string();
 
-    if correct_pin {
-        db.execute(
-            "UPDATE account SET balance = balance + ?1 WHERE account_number=?2",
-            rusqlite::params![amount, account_number],
-        )?;
+        let mut rng = thread_rng();
+        let mut pin: Vec<String> = Vec::new();
 
-        let query_string = format!(
-            "SELECT balance FROM account where account_number='{}';",
-            account_number
-        );
+        // Six digit pin
+        for _ in 1..=6 {
+            let y = rng.gen_range(0..=9).to_string();
+            pin.push(y);
+        }
 
-        let amount_from_db: usize = db.query_row(&query_string, [], |row| row.get(0))?;
+        let pin: String = String::from_iter(pin);
+        let pin_hash = hash_pin(&pin)?;
 
-        println!(
-            "The account number `{}` now has a balance of `{}`.\n",
-            &account_number, &amount_from_db
-        );
-    } else {
-        eprintln!("Wrong pin. Try again...");
+        let newest_max_id = with_transaction(&mut self.conn, |tx| {
+            let newest_max_id: u64 =
+                tx.query_row("SELECT COALESCE(MAX(id), 0) FROM account", [], |row| row.get(0))?;
+            let newest_max_id = newest_max_id + 1;
+
+            tx.execute(
+                "INSERT INTO account (id, account_number, pin) VALUES (?1, ?2, ?3)",
+                rusqlite::params![newest_max_id, account_number, pin_hash],
+            )?;
+
+            tx.execute(
+                "INSERT INTO balances (account_number, denom, amount) VALUES (?1, ?2, ?3)",
+                rusqlite::params![account_number, DEFAULT_DENOM, balance as i64],
+            )?;
+
+            record_ledger_entry(
+                tx,
+                &account_number,
+                LEDGER_ACCOUNT_OPENED,
+                DEFAULT_DENOM,
+                balance as i64,
+                None,
+                balance,
+                None,
+            )?;
+
+            Ok(newest_max_id)
+        })?;
+
+        Ok(Account {
+            id: newest_max_id,
+            account_number,
+            pin,
+        })
     }
-    Ok(())
-}
 
-pub fn transfer(
-    amount: &str,
-    pin: &str,
-    origin_account: &str,
-    target_account: &str,
-) -> Result<(Account, Account)> {
-    if *origin_account == *target_account {
-        return Err(rusqlite::Error::QueryReturnedNoRows); // Makes sense. We haven't returned any.
+    /// Verifies `pin` against the hash stored for `account_number` - the only
+    /// sanctioned way to check a PIN. A legacy plaintext pin is matched
+    /// directly and upgraded to a hash in place on success.
+    pub fn verify_pin(&self, account_number: &str, pin: &str) -> BankResult<bool> {
+        let account = self.fetch_account(account_number)?;
+
+        match PasswordHash::new(&account.pin) {
+            Ok(parsed_hash) => Ok(Argon2::default()
+                .verify_password(pin.as_bytes(), &parsed_hash)
+                .is_ok()),
+            Err(_) => {
+                let matches = account.pin == pin;
+                if matches {
+                    let upgraded = hash_pin(pin)?;
+                    self.conn
+                        .prepare_cached("UPDATE account SET pin=?1 WHERE account_number=?2")?
+                        .execute(rusqlite::params![upgraded, account_number])?;
+                }
+                Ok(matches)
+            }
+        }
     }
 
-    // Create new binding
-    let origin_account = fetch_account(origin_account)?;
-    let target_account = fetch_account(target_account)?;
+    pub fn deposit(&mut self, amount: &str, pin: &str, account_number: &str, denom: &str) -> BankResult<()> {
+        if self.verify_pin(account_number, pin)? {
+            let amount = amount.parse::<u64>().map_err(|_| BankError::InvalidAmount)?;
 
-    let correct_pin = origin_account.pin == pin;
+            let new_balance = with_transaction(&mut self.conn, |tx| {
+                tx.execute(
+                    "INSERT INTO balances (account_number, denom, amount) VALUES (?1, ?2, ?3)
+                     ON CONFLICT(account_number, denom) DO UPDATE SET amount = amount + excluded.amount",
+                    rusqlite::params![account_number, denom, amount],
+                )?;
 
-    if correct_pin {
-        let amount = amount
-            .parse::<u64>().map_err(|_| {
-                rusqlite::Error::QueryReturnedNoRows
+                let new_balance: u64 = tx.query_row(
+                    "SELECT amount FROM balances WHERE account_number=?1 AND denom=?2",
+                    rusqlite::params![account_number, denom],
+                    |row| row.get(0),
+                )?;
+
+                record_ledger_entry(tx, account_number, LEDGER_DEPOSIT, denom, amount as i64, None, new_balance, None)?;
+
+                Ok(new_balance)
             })?;
 
-        if amount > origin_account.balance {
+            println!(
+                "The account number `{}` now has a balance of `{}` {}.\n",
+                &account_number, new_balance, denom
+            );
         } else {
-            let db = initialise_bankdb()?;
-            // Add money to account 2
-            db.execute(
-                "UPDATE account SET balance = balance + ?1 WHERE account_number=?2",
-                rusqlite::params![amount as i64, &target_account.account_number],
-            )?;
-            
-            db.execute(
-                "UPDATE account SET balance = balance - ?1 WHERE account_number=?2",
-                rusqlite::params![amount as i64, &origin_account.account_number],
-            )?;
-            
-        };
-    } else {
-        return Err(rusqlite::Error::QueryReturnedNoRows);
+            eprintln!("Wrong pin. Try again...");
+        }
+        Ok(())
     }
 
-    let origin_account = fetch_account(&origin_account.account_number)?;
-    let target_account = fetch_account(&target_account.account_number)?;
+    pub fn transfer(
+        &mut self,
+        amount: &str,
+        pin: &str,
+        origin_account: &str,
+        target_account: &str,
+        denom: &str,
+    ) -> BankResult<(Account, Account)> {
+        if *origin_account == *target_account {
+            return Err(BankError::SameAccount);
+        }
 
-    Ok((origin_account, target_account))
-}
+        // Create new binding
+        let origin_account = self.fetch_account(origin_account)?;
+        let target_account = self.fetch_account(target_account)?;
+
+        if !self.verify_pin(&origin_account.account_number, pin)? {
+            return Err(BankError::WrongPin);
+        }
+
+        let amount = amount
+            .parse::<u64>()
+            .map_err(|_| BankError::InvalidAmount)?;
+
+        with_transaction(&mut self.conn, |tx| {
+            let balance = balance_in_denom(tx, &origin_account.account_number, denom)?;
 
-pub fn withdraw(amount: &str, pin: &str, account_number: &str) -> Result<()> {
-    let db = initialise_bankdb()?;
-    let query_string = format!(
-        "SELECT pin, balance FROM account WHERE account_number='{}';",
-        account_number
-    );
-
-    let (pin_from_db, balance_from_db): (String, u64) = db.query_row(&query_string, [], |row| {
-        Ok((row.get(0)?, row.get(1)?))
-    })?;
-
-    if pin_from_db == pin {
-        let amount = amount.parse::<u64>().map_err(|_| rusqlite::Error::InvalidParameterName("Invalid amount".into()))?;
-        if balance_from_db >= amount {
-            db.execute(
-                "UPDATE account SET balance = balance - ?1 WHERE account_number=?2",
-                rusqlite::params![amount, account_number],
+            if amount > balance {
+                return Err(BankError::InsufficientFunds);
+            }
+
+            tx.execute(
+                "UPDATE balances SET amount = amount - ?1 WHERE account_number=?2 AND denom=?3",
+                rusqlite::params![amount as i64, &origin_account.account_number, denom],
             )?;
 
-            println!(
-                "The account number `{}` now has a balance of `{}`.\n",
-                account_number,
-                balance_from_db - amount
-            );
+            tx.execute(
+                "INSERT INTO balances (account_number, denom, amount) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(account_number, denom) DO UPDATE SET amount = amount + excluded.amount",
+                rusqlite::params![&target_account.account_number, denom, amount as i64],
+            )?;
+
+            let origin_balance = balance - amount;
+            let target_balance = balance_in_denom(tx, &target_account.account_number, denom)?;
+
+            let transfer_id = record_ledger_entry(
+                tx,
+                &origin_account.account_number,
+                LEDGER_TRANSFER_DEBIT,
+                denom,
+                -(amount as i64),
+                Some(&target_account.account_number),
+                origin_balance,
+                None,
+            )?;
+            tx.execute(
+                "UPDATE ledger SET transfer_id = ?1 WHERE id = ?1",
+                rusqlite::params![transfer_id],
+            )?;
+
+            record_ledger_entry(
+                tx,
+                &target_account.account_number,
+                LEDGER_TRANSFER_CREDIT,
+                denom,
+                amount as i64,
+                Some(&origin_account.account_number),
+                target_balance,
+                Some(transfer_id),
+            )?;
+
+            Ok(())
+        })?;
+
+        let origin_account = self.fetch_account(&origin_account.account_number)?;
+        let target_account = self.fetch_account(&target_account.account_number)?;
+
+        Ok((origin_account, target_account))
+    }
+
+    pub fn withdraw(&mut self, amount: &str, pin: &str, account_number: &str, denom: &str) -> BankResult<()> {
+        if self.verify_pin(account_number, pin)? {
+            let amount = amount.parse::<u64>().map_err(|_| BankError::InvalidAmount)?;
+
+            let result = with_transaction(&mut self.conn, |tx| {
+                let balance = balance_in_denom(tx, account_number, denom)?;
+                if amount > balance {
+                    return Err(BankError::InsufficientFunds);
+                }
+
+                tx.execute(
+                    "UPDATE balances SET amount = amount - ?1 WHERE account_number=?2 AND denom=?3",
+                    rusqlite::params![amount, account_number, denom],
+                )?;
+
+                let new_balance = balance - amount;
+                record_ledger_entry(
+                    tx,
+                    account_number,
+                    LEDGER_WITHDRAWAL,
+                    denom,
+                    -(amount as i64),
+                    None,
+                    new_balance,
+                    None,
+                )?;
+
+                Ok(new_balance)
+            });
+
+            match result {
+                Ok(new_balance) => println!(
+                    "The account number `{}` now has a balance of `{}` {}.\n",
+                    account_number, new_balance, denom
+                ),
+                Err(BankError::InsufficientFunds) => eprintln!("Insufficient funds."),
+                Err(err) => return Err(err),
+            }
         } else {
-            eprintln!("Insufficient funds.");
+            eprintln!("Wrong pin. Try again...");
         }
-    } else {
-        eprintln!("Wrong pin. Try again...");
+        Ok(())
     }
-    Ok(())
-}
 
-pub fn delete_account(account_number: &str, pin: &str) -> Result<()> {
-    let db = initialise_bankdb()?;
-    let query_string = format!(
-        "SELECT pin FROM account where account_number='{}';",
-        &account_number
-    );
+    /// Returns every denomination held by `account_number` and its amount.
+    pub fn all_balances(&self, account_number: &str) -> BankResult<Vec<(String, u64)>> {
+        let balances = self
+            .conn
+            .prepare_cached("SELECT denom, amount FROM balances WHERE account_number=?1 ORDER BY denom")?
+            .query_map(rusqlite::params![account_number], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(balances)
+    }
 
-    let pin_from_db: String = db.query_row(&query_string, [], |row| row.get(0))?;
-    let correct_pin = { pin_from_db == pin };
+    pub fn delete_account(&mut self, account_number: &str, pin: &str) -> BankResult<()> {
+        if self.verify_pin(account_number, pin)? {
+            let held_balances = self.all_balances(account_number)?;
+
+            with_transaction(&mut self.conn, |tx| {
+                for (denom, amount) in &held_balances {
+                    record_ledger_entry(
+                        tx,
+                        account_number,
+                        LEDGER_ACCOUNT_DELETED,
+                        denom,
+                        -(*amount as i64),
+                        None,
+                        0,
+                        None,
+                    )?;
+                }
+
+                tx.execute(
+                    "DELETE FROM account WHERE account_number=?1",
+                    rusqlite::params![account_number],
+                )?;
+                tx.execute(
+                    "DELETE FROM balances WHERE account_number=?1",
+                    rusqlite::params![account_number],
+                )?;
+                Ok(())
+            })?;
 
-    if correct_pin {
-        db.execute(
-            "DELETE FROM account WHERE account_number=?1",
-            rusqlite::params![account_number],
-        )?;
+            println!("DELETED ACCOUNT: {}", &account_number);
+        } else {
+            eprintln!("Wrong pin. Try again...");
+        }
+        Ok(())
+    }
 
-        println!("DELETED ACCOUNT: {}", &account_number);
-    } else {
-        eprintln!("Wrong pin. Try again...");
+    /// Returns the account's history, oldest first, optionally restricted to
+    /// entries recorded at or after `since` (a unix timestamp).
+    pub fn statement(&self, account_number: &str, since: Option<i64>) -> BankResult<Vec<LedgerEntry>> {
+        let entries = self
+            .conn
+            .prepare_cached(
+                "SELECT id, account_number, kind, denom, amount, counterparty, balance, timestamp, transfer_id
+                 FROM ledger
+                 WHERE account_number=?1 AND timestamp >= ?2
+                 ORDER BY id",
+            )?
+            .query_map(rusqlite::params![account_number, since.unwrap_or(0)], |row| {
+                Ok(LedgerEntry {
+                    id: row.get(0)?,
+                    account_number: row.get(1)?,
+                    kind: row.get(2)?,
+                    denom: row.get(3)?,
+                    amount: row.get(4)?,
+                    counterparty: row.get(5)?,
+                    balance: row.get(6)?,
+                    timestamp: row.get(7)?,
+                    transfer_id: row.get(8)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(entries)
     }
-    Ok(())
-}
 
-pub fn show_balance(account_number: &str) -> Result<()> {
-    let db = initialise_bankdb()?;
-    let query_string = format!(
-        "SELECT balance FROM account where account_number='{}';",
-        account_number
-    );
+    /// Writes every account, balance and ledger row to `path`, encrypted
+    /// under `passphrase`, for later repopulation with [`Bank::restore`].
+    pub fn backup(&self, path: &Path, passphrase: &str) -> BankResult<()> {
+        let snapshot = backup::Snapshot::read(&self.conn)?;
+        let ciphertext = backup::encrypt(&snapshot.encode(), passphrase)?;
+        std::fs::write(path, ciphertext)?;
+        Ok(())
+    }
 
-    let amount_from_db: usize = db.query_row(&query_string, [], |row| row.get(0))?;
+    /// Decrypts `path` with `passphrase` and repopulates the database from
+    /// it. Refuses to touch a database that already has accounts in it
+    /// unless `force` is set.
+    pub fn restore(&mut self, path: &Path, passphrase: &str, force: bool) -> BankResult<()> {
+        let has_accounts: bool = self
+            .conn
+            .query_row("SELECT EXISTS(SELECT 1 FROM account)", [], |row| row.get(0))?;
+        if has_accounts && !force {
+            return Err(BankError::DatabaseNotEmpty);
+        }
 
-    println!(
-        "The account number `{}` now has a balance of `{}`.\n",
-        &account_number, &amount_from_db
-    );
-    Ok(())
-}
+        let ciphertext = std::fs::read(path)?;
+        let plaintext = backup::decrypt(&ciphertext, passphrase)?;
+        let snapshot = backup::Snapshot::decode(&plaintext)?;
 
-fn fetch_account(account: &str) -> Result<Account> {
-    let db = initialise_bankdb()?;
-    let mut stmt = db.prepare("SELECT id, account_number, balance, pin FROM account")?;
-    let accounts = stmt.query_map([], |row| {
-        Ok(Account {
-            id: row.get(0)?,
-            account_number: row.get(1)?,
-            balance: row.get(2)?,
-            pin: row.get(3)?,
+        with_transaction(&mut self.conn, |tx| {
+            tx.execute("DELETE FROM ledger", [])?;
+            tx.execute("DELETE FROM balances", [])?;
+            tx.execute("DELETE FROM account", [])?;
+            snapshot.write(tx)?;
+            Ok(())
         })
-    })?;
+    }
+
+    /// Prints one line per denomination the account holds.
+    pub fn show_balance(&self, account_number: &str) -> BankResult<()> {
+        let balances = self.all_balances(account_number)?;
+
+        if balances.is_empty() {
+            println!("The account number `{}` has no recorded balance.\n", &account_number);
+        } else {
+            for (denom, amount) in balances {
+                println!(
+                    "The account number `{}` now has a balance of `{}` {}.\n",
+                    &account_number, amount, denom
+                );
+            }
+        }
+        Ok(())
+    }
 
-    let accounts = accounts.flatten().find(|acc| acc.account_number == account);
-    if let Some(fetched_account) = accounts {
-        Ok(fetched_account)
-    } else {
-        Err(rusqlite::Error::QueryReturnedNoRows)
+    fn fetch_account(&self, account_number: &str) -> BankResult<Account> {
+        self.conn
+            .prepare_cached("SELECT id, account_number, pin FROM account WHERE account_number=?1")?
+            .query_row(rusqlite::params![account_number], |row| {
+                Ok(Account {
+                    id: row.get(0)?,
+                    account_number: row.get(1)?,
+                    pin: row.get(2)?,
+                })
+            })
+            .map_err(BankError::from)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    
+
     #[test]
-    fn created_account_is_correct_fetched_from_db() -> Result<()> {
+    fn created_account_is_correct_fetched_from_db() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut bank = Bank::open()?;
         let account_number = AccountNumber::new(10);
-        create_account(&account_number, 100)?;
-        let account = fetch_account(&account_number.to_string())?;
+        bank.create_account(&account_number, 100)?;
+        let account = bank.fetch_account(&account_number.to_string())?;
 
         assert_eq!(account.account_number, account_number.to_string());
-        assert_eq!(account.balance, 100);
+        assert_eq!(
+            bank.all_balances(&account_number.to_string())?,
+            vec![(DEFAULT_DENOM.to_string(), 100)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn created_account_pin_verifies_and_hash_is_not_plaintext() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut bank = Bank::open()?;
+        let account_number = AccountNumber::new(10);
+        let account = bank.create_account(&account_number, 100)?;
+
+        let stored = bank.fetch_account(&account_number.to_string())?;
+        assert_ne!(stored.pin, account.pin);
+
+        assert!(bank.verify_pin(&account_number.to_string(), &account.pin)?);
+        assert!(!bank.verify_pin(&account_number.to_string(), "000000")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_plaintext_pin_verifies_and_is_upgraded_to_a_hash() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut bank = Bank::open()?;
+        let account_number = AccountNumber::new(10);
+        bank.create_account(&account_number, 0)?;
+
+        bank.conn.execute(
+            "UPDATE account SET pin='000000' WHERE account_number=?1",
+            rusqlite::params![account_number.to_string()],
+        )?;
+
+        assert!(bank.verify_pin(&account_number.to_string(), "000000")?);
+
+        let upgraded = bank.fetch_account(&account_number.to_string())?;
+        assert_ne!(upgraded.pin, "000000");
+        assert!(bank.verify_pin(&account_number.to_string(), "000000")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn account_number_with_sql_metacharacters_does_not_corrupt_other_accounts() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut bank = Bank::open()?;
+        let account_number = AccountNumber::new(10);
+        bank.create_account(&account_number, 500)?;
+
+        let malicious_account_number = "' OR '1'='1'; DROP TABLE account; --";
+
+        // None of these should touch any row, let alone the seeded account,
+        // since the account number is bound as a parameter rather than
+        // spliced into the query string.
+        assert!(bank.deposit("100", "000000", malicious_account_number, DEFAULT_DENOM).is_err());
+        assert!(bank.withdraw("100", "000000", malicious_account_number, DEFAULT_DENOM).is_err());
+        assert!(bank.all_balances(malicious_account_number)?.is_empty());
+
+        assert_eq!(
+            bank.all_balances(&account_number.to_string())?,
+            vec![(DEFAULT_DENOM.to_string(), 500)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn transferred_balance_is_correct() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut bank = Bank::open()?;
+        let origin_account_number = AccountNumber::new(10);
+        let target_account_number = AccountNumber::new(10);
+
+        let origin_account = bank.create_account(&origin_account_number, 10000)?;
+        let target_account = bank.create_account(&target_account_number, 0)?;
+
+        let pin = origin_account.pin.clone();
+        bank.transfer(
+            "10000",
+            &pin,
+            &origin_account.account_number,
+            &target_account.account_number,
+            DEFAULT_DENOM,
+        )?;
+
+        assert_eq!(
+            bank.all_balances(&origin_account.account_number)?,
+            vec![(DEFAULT_DENOM.to_string(), 0)]
+        );
+        assert_eq!(
+            bank.all_balances(&target_account.account_number)?,
+            vec![(DEFAULT_DENOM.to_string(), 10000)]
+        );
 
         Ok(())
     }
 
     #[test]
-    fn transferred_balance_is_correct() -> Result<()> {
+    fn transfer_with_insufficient_funds_leaves_balances_untouched() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut bank = Bank::open()?;
         let origin_account_number = AccountNumber::new(10);
         let target_account_number = AccountNumber::new(10);
 
-        create_account(&origin_account_number, 10000)?;
-        create_account(&target_account_number, 0)?;
+        let origin_account = bank.create_account(&origin_account_number, 100)?;
+        let target_account = bank.create_account(&target_account_number, 0)?;
+
+        let pin = origin_account.pin.clone();
+        let result = bank.transfer(
+            "10000",
+            &pin,
+            &origin_account.account_number,
+            &target_account.account_number,
+            DEFAULT_DENOM,
+        );
+
+        assert!(matches!(result, Err(BankError::InsufficientFunds)));
+
+        assert_eq!(
+            bank.all_balances(&origin_account.account_number)?,
+            vec![(DEFAULT_DENOM.to_string(), 100)]
+        );
+        assert_eq!(
+            bank.all_balances(&target_account.account_number)?,
+            vec![(DEFAULT_DENOM.to_string(), 0)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn deposit_rejects_a_negative_amount() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut bank = Bank::open()?;
+        let account_number = AccountNumber::new(10);
+        let account = bank.create_account(&account_number, 100)?;
+
+        let result = bank.deposit("-500", &account.pin, &account.account_number, DEFAULT_DENOM);
+        assert!(matches!(result, Err(BankError::InvalidAmount)));
+
+        assert_eq!(
+            bank.all_balances(&account.account_number)?,
+            vec![(DEFAULT_DENOM.to_string(), 100)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn deposit_and_withdraw_keep_denominations_independent() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut bank = Bank::open()?;
+        let account_number = AccountNumber::new(10);
+        let account = bank.create_account(&account_number, 0)?;
+
+        bank.deposit("50", &account.pin, &account.account_number, "gold")?;
+        bank.deposit("20", &account.pin, &account.account_number, "silver")?;
+        bank.withdraw("5", &account.pin, &account.account_number, "gold")?;
+
+        let mut balances = bank.all_balances(&account.account_number)?;
+        balances.sort();
+
+        let mut expected = vec![
+            (DEFAULT_DENOM.to_string(), 0),
+            ("gold".to_string(), 45),
+            ("silver".to_string(), 20),
+        ];
+        expected.sort();
 
-        let origin_account = fetch_account(&origin_account_number.to_string())?;
-        let target_account = fetch_account(&target_account_number.to_string())?;
+        assert_eq!(balances, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn transfer_writes_linked_debit_and_credit_ledger_entries() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut bank = Bank::open()?;
+        let origin_account_number = AccountNumber::new(10);
+        let target_account_number = AccountNumber::new(10);
+
+        let origin_account = bank.create_account(&origin_account_number, 100)?;
+        let target_account = bank.create_account(&target_account_number, 0)?;
 
         let pin = origin_account.pin.clone();
-        transfer("10000", &pin, &origin_account.account_number, &target_account.account_number)?;
+        bank.transfer(
+            "40",
+            &pin,
+            &origin_account.account_number,
+            &target_account.account_number,
+            DEFAULT_DENOM,
+        )?;
+
+        let origin_statement = bank.statement(&origin_account.account_number, None)?;
+        let debit = origin_statement
+            .iter()
+            .find(|entry| entry.kind == LEDGER_TRANSFER_DEBIT)
+            .expect("debit entry recorded");
+        assert_eq!(debit.amount, -40);
+        assert_eq!(debit.balance, 60);
+        assert_eq!(debit.counterparty.as_deref(), Some(target_account.account_number.as_str()));
+
+        let target_statement = bank.statement(&target_account.account_number, None)?;
+        let credit = target_statement
+            .iter()
+            .find(|entry| entry.kind == LEDGER_TRANSFER_CREDIT)
+            .expect("credit entry recorded");
+        assert_eq!(credit.amount, 40);
+        assert_eq!(credit.balance, 40);
+        assert_eq!(credit.transfer_id, Some(debit.id as i64));
+
+        Ok(())
+    }
+
+    #[test]
+    fn backup_and_restore_round_trips_accounts_balances_and_ledger() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        // `restore` wipes the whole database, so this test - unlike the
+        // others in this file - needs a database of its own rather than the
+        // shared `mock_bank.s3db`.
+        let db_path = std::env::temp_dir().join(format!("bank_test_restore_{}.s3db", std::process::id()));
+        let _ = std::fs::remove_file(&db_path);
+        let mut bank = Bank::open_at(&db_path)?;
+
+        let account_number = AccountNumber::new(10);
+        let account = bank.create_account(&account_number, 100)?;
+        bank.deposit("50", &account.pin, &account.account_number, DEFAULT_DENOM)?;
+
+        let backup_path = std::env::temp_dir().join(format!("bank_test_backup_{}.enc", std::process::id()));
+        bank.backup(&backup_path, "correct horse battery staple")?;
+
+        assert!(matches!(
+            bank.restore(&backup_path, "correct horse battery staple", false),
+            Err(BankError::DatabaseNotEmpty)
+        ));
+
+        bank.restore(&backup_path, "correct horse battery staple", true)?;
+
+        assert_eq!(
+            bank.all_balances(&account.account_number)?,
+            vec![(DEFAULT_DENOM.to_string(), 150)]
+        );
+        assert_eq!(bank.statement(&account.account_number, None)?.len(), 2);
 
-        let origin_account = fetch_account(&origin_account.account_number)?;
-        let target_account = fetch_account(&target_account.account_number)?;
+        assert!(matches!(
+            bank.restore(&backup_path, "wrong passphrase", true),
+            Err(BankError::BadBackup)
+        ));
 
-        assert_eq!(origin_account.balance, 0);
-        assert_eq!(target_account.balance, 10000);
+        std::fs::remove_file(&backup_path)?;
+        std::fs::remove_file(&db_path)?;
 
         Ok(())
     }
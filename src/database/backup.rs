@@ -0,0 +1,320 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Encrypted full-database backup and restore. A backup is a flat,
+//! length-prefixed encoding of every row, sealed with XChaCha20-Poly1305
+//! under a key derived from the passphrase with Argon2id.
+
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand::RngCore;
+use rusqlite::Connection;
+
+use super::{BankError, BankResult};
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const KEY_LEN: usize = 32;
+
+/// Everything needed to repopulate a fresh database.
+pub(super) struct Snapshot {
+    accounts: Vec<(u64, String, String)>,
+    balances: Vec<(String, String, i64)>,
+    ledger: Vec<(u64, String, String, String, i64, Option<String>, u64, i64, Option<i64>)>,
+}
+
+impl Snapshot {
+    pub(super) fn read(conn: &Connection) -> BankResult<Self> {
+        let accounts = conn
+            .prepare_cached("SELECT id, account_number, pin FROM account")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let balances = conn
+            .prepare_cached("SELECT account_number, denom, amount FROM balances")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let ledger = conn
+            .prepare_cached(
+                "SELECT id, account_number, kind, denom, amount, counterparty, balance, timestamp, transfer_id
+                 FROM ledger",
+            )?
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                    row.get(6)?,
+                    row.get(7)?,
+                    row.get(8)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(Self { accounts, balances, ledger })
+    }
+
+    pub(super) fn write(&self, tx: &rusqlite::Transaction) -> BankResult<()> {
+        for (id, account_number, pin) in &self.accounts {
+            tx.execute(
+                "INSERT INTO account (id, account_number, pin) VALUES (?1, ?2, ?3)",
+                rusqlite::params![id, account_number, pin],
+            )?;
+        }
+
+        for (account_number, denom, amount) in &self.balances {
+            tx.execute(
+                "INSERT INTO balances (account_number, denom, amount) VALUES (?1, ?2, ?3)",
+                rusqlite::params![account_number, denom, amount],
+            )?;
+        }
+
+        for (id, account_number, kind, denom, amount, counterparty, balance, timestamp, transfer_id) in &self.ledger {
+            tx.execute(
+                "INSERT INTO ledger (id, account_number, kind, denom, amount, counterparty, balance, timestamp, transfer_id)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                rusqlite::params![id, account_number, kind, denom, amount, counterparty, balance, timestamp, transfer_id],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        write_u32(&mut buf, self.accounts.len() as u32);
+        for (id, account_number, pin) in &self.accounts {
+            buf.extend_from_slice(&id.to_le_bytes());
+            write_str(&mut buf, account_number);
+            write_str(&mut buf, pin);
+        }
+
+        write_u32(&mut buf, self.balances.len() as u32);
+        for (account_number, denom, amount) in &self.balances {
+            write_str(&mut buf, account_number);
+            write_str(&mut buf, denom);
+            buf.extend_from_slice(&amount.to_le_bytes());
+        }
+
+        write_u32(&mut buf, self.ledger.len() as u32);
+        for (id, account_number, kind, denom, amount, counterparty, balance, timestamp, transfer_id) in &self.ledger {
+            buf.extend_from_slice(&id.to_le_bytes());
+            write_str(&mut buf, account_number);
+            write_str(&mut buf, kind);
+            write_str(&mut buf, denom);
+            buf.extend_from_slice(&amount.to_le_bytes());
+            write_opt_str(&mut buf, counterparty.as_deref());
+            buf.extend_from_slice(&balance.to_le_bytes());
+            buf.extend_from_slice(&timestamp.to_le_bytes());
+            write_opt_i64(&mut buf, *transfer_id);
+        }
+
+        buf
+    }
+
+    pub(super) fn decode(buf: &[u8]) -> BankResult<Self> {
+        let mut cursor = 0usize;
+
+        let account_count = read_u32(buf, &mut cursor)?;
+        let mut accounts = Vec::with_capacity(account_count as usize);
+        for _ in 0..account_count {
+            let id = read_u64(buf, &mut cursor)?;
+            let account_number = read_str(buf, &mut cursor)?;
+            let pin = read_str(buf, &mut cursor)?;
+            accounts.push((id, account_number, pin));
+        }
+
+        let balance_count = read_u32(buf, &mut cursor)?;
+        let mut balances = Vec::with_capacity(balance_count as usize);
+        for _ in 0..balance_count {
+            let account_number = read_str(buf, &mut cursor)?;
+            let denom = read_str(buf, &mut cursor)?;
+            let amount = read_i64(buf, &mut cursor)?;
+            balances.push((account_number, denom, amount));
+        }
+
+        let ledger_count = read_u32(buf, &mut cursor)?;
+        let mut ledger = Vec::with_capacity(ledger_count as usize);
+        for _ in 0..ledger_count {
+            let id = read_u64(buf, &mut cursor)?;
+            let account_number = read_str(buf, &mut cursor)?;
+            let kind = read_str(buf, &mut cursor)?;
+            let denom = read_str(buf, &mut cursor)?;
+            let amount = read_i64(buf, &mut cursor)?;
+            let counterparty = read_opt_str(buf, &mut cursor)?;
+            let balance = read_u64(buf, &mut cursor)?;
+            let timestamp = read_i64(buf, &mut cursor)?;
+            let transfer_id = read_opt_i64(buf, &mut cursor)?;
+            ledger.push((id, account_number, kind, denom, amount, counterparty, balance, timestamp, transfer_id));
+        }
+
+        Ok(Self { accounts, balances, ledger })
+    }
+}
+
+fn write_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_str(buf: &mut Vec<u8>, value: &str) {
+    write_u32(buf, value.len() as u32);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+fn write_opt_str(buf: &mut Vec<u8>, value: Option<&str>) {
+    match value {
+        Some(value) => {
+            buf.push(1);
+            write_str(buf, value);
+        }
+        None => buf.push(0),
+    }
+}
+
+fn write_opt_i64(buf: &mut Vec<u8>, value: Option<i64>) {
+    match value {
+        Some(value) => {
+            buf.push(1);
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+        None => buf.push(0),
+    }
+}
+
+fn read_u32(buf: &[u8], cursor: &mut usize) -> BankResult<u32> {
+    let bytes = buf.get(*cursor..*cursor + 4).ok_or(BankError::BadBackup)?;
+    *cursor += 4;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(buf: &[u8], cursor: &mut usize) -> BankResult<u64> {
+    let bytes = buf.get(*cursor..*cursor + 8).ok_or(BankError::BadBackup)?;
+    *cursor += 8;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_i64(buf: &[u8], cursor: &mut usize) -> BankResult<i64> {
+    let bytes = buf.get(*cursor..*cursor + 8).ok_or(BankError::BadBackup)?;
+    *cursor += 8;
+    Ok(i64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_str(buf: &[u8], cursor: &mut usize) -> BankResult<String> {
+    let len = read_u32(buf, cursor)? as usize;
+    let bytes = buf.get(*cursor..*cursor + len).ok_or(BankError::BadBackup)?;
+    *cursor += len;
+    String::from_utf8(bytes.to_vec()).map_err(|_| BankError::BadBackup)
+}
+
+fn read_opt_str(buf: &[u8], cursor: &mut usize) -> BankResult<Option<String>> {
+    let tag = *buf.get(*cursor).ok_or(BankError::BadBackup)?;
+    *cursor += 1;
+    match tag {
+        0 => Ok(None),
+        1 => Ok(Some(read_str(buf, cursor)?)),
+        _ => Err(BankError::BadBackup),
+    }
+}
+
+fn read_opt_i64(buf: &[u8], cursor: &mut usize) -> BankResult<Option<i64>> {
+    let tag = *buf.get(*cursor).ok_or(BankError::BadBackup)?;
+    *cursor += 1;
+    match tag {
+        0 => Ok(None),
+        1 => Ok(Some(read_i64(buf, cursor)?)),
+        _ => Err(BankError::BadBackup),
+    }
+}
+
+/// Derives a 256-bit key from `passphrase` and `salt` with Argon2id.
+fn derive_key(passphrase: &str, salt: &[u8]) -> BankResult<[u8; KEY_LEN]> {
+    let mut key = [0u8; KEY_LEN];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|_| BankError::BadBackup)?;
+    Ok(key)
+}
+
+/// Encrypts `plaintext` under `passphrase`, returning `salt || nonce ||
+/// ciphertext`. A fresh salt and nonce are generated for every call.
+pub(super) fn encrypt(plaintext: &[u8], passphrase: &str) -> BankResult<Vec<u8>> {
+    let mut rng = rand::thread_rng();
+
+    let mut salt = [0u8; SALT_LEN];
+    rng.fill_bytes(&mut salt);
+    let key = derive_key(passphrase, &salt)?;
+
+    let mut nonce_bytes = [0u8; NONCE_LEN];
+    rng.fill_bytes(&mut nonce_bytes);
+    let nonce = XNonce::from_slice(&nonce_bytes);
+
+    let cipher = XChaCha20Poly1305::new(&key.into());
+    let ciphertext = cipher
+        .encrypt(nonce, plaintext)
+        .map_err(|_| BankError::BadBackup)?;
+
+    let mut out = Vec::with_capacity(SALT_LEN + NONCE_LEN + ciphertext.len());
+    out.extend_from_slice(&salt);
+    out.extend_from_slice(&nonce_bytes);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/// Reverses [`encrypt`], failing with [`BankError::BadBackup`] if the
+/// passphrase is wrong or the file has been tampered with or truncated.
+pub(super) fn decrypt(data: &[u8], passphrase: &str) -> BankResult<Vec<u8>> {
+    if data.len() < SALT_LEN + NONCE_LEN {
+        return Err(BankError::BadBackup);
+    }
+
+    let (salt, rest) = data.split_at(SALT_LEN);
+    let (nonce_bytes, ciphertext) = rest.split_at(NONCE_LEN);
+
+    let key = derive_key(passphrase, salt)?;
+    let nonce = XNonce::from_slice(nonce_bytes);
+
+    let cipher = XChaCha20Poly1305::new(&key.into());
+    cipher
+        .decrypt(nonce, ciphertext)
+        .map_err(|_| BankError::BadBackup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypted_roundtrip_recovers_the_plaintext() -> BankResult<()> {
+        let plaintext = b"top secret account data".to_vec();
+        let ciphertext = encrypt(&plaintext, "correct horse battery staple")?;
+        let decrypted = decrypt(&ciphertext, "correct horse battery staple")?;
+        assert_eq!(decrypted, plaintext);
+        Ok(())
+    }
+
+    #[test]
+    fn wrong_passphrase_fails_to_decrypt() -> BankResult<()> {
+        let ciphertext = encrypt(b"top secret account data", "correct horse battery staple")?;
+        assert!(matches!(decrypt(&ciphertext, "wrong passphrase"), Err(BankError::BadBackup)));
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_survives_encode_decode() {
+        let snapshot = Snapshot {
+            accounts: vec![(1, "acc".to_string(), "hash".to_string())],
+            balances: vec![("acc".to_string(), "default".to_string(), 100)],
+            ledger: vec![(1, "acc".to_string(), "deposit".to_string(), "default".to_string(), 100, None, 100, 0, None)],
+        };
+
+        let decoded = Snapshot::decode(&snapshot.encode()).expect("decodes cleanly");
+        assert_eq!(decoded.accounts, snapshot.accounts);
+        assert_eq!(decoded.balances, snapshot.balances);
+        assert_eq!(decoded.ledger, snapshot.ledger);
+    }
+}
@@ -0,0 +1,104 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Versioned schema migrations for the bank database. `apply` runs every
+//! `MIGRATIONS` entry past the `user_version` pragma, in order, inside one
+//! transaction.
+
+use rusqlite::{Connection, Result};
+
+struct Migration {
+    up: &'static str,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        // `pin` holds an Argon2id PHC-format hash string. A plaintext PIN left
+        // by an older database stays until it first verifies, when
+        // `Bank::verify_pin` replaces it with its hash.
+        up: "CREATE TABLE IF NOT EXISTS account(
+id INTEGER PRIMARY KEY,
+account_number TEXT,
+pin TEXT,
+balance INTEGER DEFAULT 0
+)",
+    },
+    Migration {
+        // One row per denomination an account holds.
+        up: "CREATE TABLE IF NOT EXISTS balances(
+account_number TEXT NOT NULL,
+denom TEXT NOT NULL,
+amount INTEGER NOT NULL DEFAULT 0,
+PRIMARY KEY (account_number, denom)
+)",
+    },
+    Migration {
+        // Fold the old single-currency `account.balance` column into `balances`, then drop it.
+        up: "INSERT INTO balances (account_number, denom, amount)
+SELECT account_number, 'default', balance FROM account WHERE balance IS NOT NULL AND balance <> 0;
+ALTER TABLE account DROP COLUMN balance;",
+    },
+    Migration {
+        // Append-only history of every balance change.
+        up: "CREATE TABLE IF NOT EXISTS ledger(
+id INTEGER PRIMARY KEY,
+account_number TEXT NOT NULL,
+kind TEXT NOT NULL,
+denom TEXT NOT NULL,
+amount INTEGER NOT NULL,
+counterparty TEXT,
+balance INTEGER NOT NULL,
+timestamp INTEGER NOT NULL,
+transfer_id INTEGER
+)",
+    },
+    Migration {
+        // Account numbers are never supposed to repeat; enforce it so a
+        // collision errors out instead of `fetch_account` binding to
+        // whichever duplicate row comes back first.
+        up: "CREATE UNIQUE INDEX IF NOT EXISTS account_account_number_unique ON account(account_number)",
+    },
+];
+
+pub(super) fn apply(db: &mut Connection) -> Result<()> {
+    let current_version: u32 = db.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+    let pending = match MIGRATIONS.get(current_version as usize..) {
+        Some(pending) if !pending.is_empty() => pending,
+        _ => return Ok(()),
+    };
+
+    let tx = db.transaction()?;
+    for (offset, migration) in pending.iter().enumerate() {
+        tx.execute_batch(migration.up)?;
+        tx.pragma_update(None, "user_version", current_version + offset as u32 + 1)?;
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fresh_and_reopened_databases_converge_on_the_latest_version() -> Result<()> {
+        let mut fresh = Connection::open_in_memory()?;
+        apply(&mut fresh)?;
+        apply(&mut fresh)?; // reopening an already-migrated db must be a no-op
+
+        let version: u32 = fresh.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        assert_eq!(version, MIGRATIONS.len() as u32);
+
+        fresh.execute(
+            "INSERT INTO account (id, account_number, pin) VALUES (1, 'acc', 'hash')",
+            [],
+        )?;
+        fresh.execute(
+            "INSERT INTO balances (account_number, denom, amount) VALUES ('acc', 'default', 100)",
+            [],
+        )?;
+
+        Ok(())
+    }
+}